@@ -0,0 +1,252 @@
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::storage::{LockError, LockMode, PosixStorage, Storage};
+use crate::{DiskMap, Error};
+
+const NONCE_LEN: usize = 24;
+
+/// A [`Storage`] backend that transparently encrypts every value file with
+/// XChaCha20-Poly1305, following the "protected file" approach used by
+/// rusty-leveldb's SGX env and bupstash's encrypted backups. Wraps another
+/// backend (normally [`PosixStorage`]) for the actual path-level operations
+/// - only file contents are touched.
+pub struct EncryptingStorage<S = PosixStorage> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<S> EncryptingStorage<S> {
+    pub fn new(inner: S, key: [u8; 32]) -> Self {
+        EncryptingStorage {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+}
+
+impl<S: Clone> Clone for EncryptingStorage<S> {
+    fn clone(&self) -> Self {
+        EncryptingStorage {
+            inner: self.inner.clone(),
+            cipher: self.cipher.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for EncryptingStorage<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptingStorage")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+enum Mode {
+    Read,
+    Write { create_new: bool },
+}
+
+/// The plaintext of a value, staged in memory. Reads are decrypted in full
+/// at [`Storage::open_read`] time (an AEAD tag can only be verified once the
+/// whole ciphertext is in hand); writes are buffered here and only hit
+/// disk, encrypted under a fresh nonce, in [`Storage::sync`].
+#[derive(Debug)]
+pub struct EncryptingFile {
+    path: PathBuf,
+    mode: Mode,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl Read for EncryptingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.buf.read(buf)
+    }
+}
+
+impl Write for EncryptingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.buf.flush()
+    }
+}
+
+impl<S: Storage> Storage for EncryptingStorage<S> {
+    type File = EncryptingFile;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<EncryptingFile> {
+        let mut ciphertext = Vec::new();
+        self.inner.open_read(path)?.read_to_end(&mut ciphertext)?;
+
+        if ciphertext.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "ciphertext too short",
+            ));
+        }
+
+        let (nonce, sealed) = ciphertext.split_at(NONCE_LEN);
+
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+
+        Ok(EncryptingFile {
+            path: path.to_owned(),
+            mode: Mode::Read,
+            buf: Cursor::new(plaintext),
+        })
+    }
+
+    fn open_write(&self, path: &Path, create_new: bool) -> io::Result<EncryptingFile> {
+        Ok(EncryptingFile {
+            path: path.to_owned(),
+            mode: Mode::Write { create_new },
+            buf: Cursor::new(Vec::new()),
+        })
+    }
+
+    /// No-op: by the time a reader's `open_read` returns here, the
+    /// ciphertext has already been read to completion and decrypted (the
+    /// inner file handle isn't retained), so there's nothing left to lock.
+    /// Writers still get atomicity from `DiskMap`'s temp-file-plus-rename.
+    fn lock(&self, _file: &EncryptingFile, _mode: LockMode) -> Result<(), LockError> {
+        Ok(())
+    }
+
+    fn sync(&self, file: &EncryptingFile) -> io::Result<()> {
+        let create_new = match file.mode {
+            Mode::Write { create_new } => create_new,
+            Mode::Read => return Err(io::Error::other("cannot sync a file opened for reading")),
+        };
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, file.buf.get_ref().as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let mut out = self.inner.open_write(&file.path, create_new)?;
+        out.write_all(&nonce)?;
+        out.write_all(&ciphertext)?;
+
+        self.inner.sync(&out)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.inner.sync_dir(dir)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+impl<K, V> DiskMap<K, V, EncryptingStorage<PosixStorage>>
+where
+    K: Serialize + DeserializeOwned,
+    K: PartialEq,
+    K: Clone,
+    V: Serialize + DeserializeOwned,
+{
+    /// Opens a map whose value files are transparently encrypted at rest
+    /// with XChaCha20-Poly1305 under `key`. On [`DiskMap::get`], a tampered
+    /// or corrupt file surfaces as [`Error::DecryptionFailed`] rather than a
+    /// generic read error.
+    pub fn open_encrypted(directory: &str, key: [u8; 32]) -> Result<Self, Error> {
+        Self::open_with_storage(directory, EncryptingStorage::new(PosixStorage, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_stores_ciphertext_on_disk() {
+        let dir = Path::new("/tmp/test_encryption_round_trip");
+        std::fs::remove_dir_all(dir).ok();
+        std::fs::create_dir_all(dir).unwrap();
+
+        let storage = EncryptingStorage::new(PosixStorage, [7u8; 32]);
+        let path = dir.join("value");
+
+        let mut f = storage.open_write(&path, true).unwrap();
+        f.write_all(b"hello").unwrap();
+        storage.sync(&f).unwrap();
+
+        let mut out = Vec::new();
+        storage.open_read(&path).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello");
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails_to_decrypt() {
+        let dir = Path::new("/tmp/test_encryption_tamper");
+        std::fs::remove_dir_all(dir).ok();
+        std::fs::create_dir_all(dir).unwrap();
+
+        let storage = EncryptingStorage::new(PosixStorage, [9u8; 32]);
+        let path = dir.join("value");
+
+        let mut f = storage.open_write(&path, true).unwrap();
+        f.write_all(b"hello").unwrap();
+        storage.sync(&f).unwrap();
+
+        let mut raw = std::fs::read(&path).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 0xff;
+        std::fs::write(&path, &raw).unwrap();
+
+        let err = storage.open_read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_different_key_cannot_decrypt() {
+        let dir = Path::new("/tmp/test_encryption_wrong_key");
+        std::fs::remove_dir_all(dir).ok();
+        std::fs::create_dir_all(dir).unwrap();
+
+        let writer = EncryptingStorage::new(PosixStorage, [1u8; 32]);
+        let path = dir.join("value");
+
+        let mut f = writer.open_write(&path, true).unwrap();
+        f.write_all(b"hello").unwrap();
+        writer.sync(&f).unwrap();
+
+        let reader = EncryptingStorage::new(PosixStorage, [2u8; 32]);
+        let err = reader.open_read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,268 @@
+use std::io::Read;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::Error;
+
+/// Tags a file as a disk-map value file, distinct from anything else that
+/// might end up in the map directory.
+const MAGIC: [u8; 4] = *b"DMAP";
+
+/// Bumped whenever the on-disk encoding of a value file changes in a way
+/// that isn't forward-compatible. [`decode`] only accepts [`CURRENT_VERSION`];
+/// anything else is migrated via [`DiskMap::upgrade`](crate::DiskMap::upgrade).
+///
+/// v1 was magic + version, no checksum. v2 added a BLAKE3 digest of the
+/// body so [`decode`] can catch bit-rot instead of handing back a bogus
+/// value (or a confusing CBOR error). v3 changed the body itself from a
+/// bare value to a `(key, value)` pair, so content-addressed filenames
+/// (see `DiskMap::filename`) can still recover the original key.
+pub(crate) const CURRENT_VERSION: u8 = 3;
+
+const CHECKSUM_LEN: usize = 32;
+const PREFIX_LEN: usize = MAGIC.len() + 1;
+const HEADER_LEN: usize = PREFIX_LEN + CHECKSUM_LEN;
+
+/// Encodes `value` as a full value-file payload: magic, format version, a
+/// BLAKE3 digest of the CBOR body, then the body itself. Mirrors bupstash's
+/// `FileTeeHasher`, just computed up front - every write in this crate is
+/// staged in memory ahead of an atomic rename anyway, so there's no
+/// streaming write to tee into.
+pub(crate) fn encode<V: Serialize>(value: &V) -> Result<Vec<u8>, Error> {
+    let body = serde_cbor::to_vec(value).map_err(|_| Error::CannotInsert)?;
+    let checksum = blake3::hash(&body);
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(CURRENT_VERSION);
+    buf.extend_from_slice(checksum.as_bytes());
+    buf.extend_from_slice(&body);
+
+    Ok(buf)
+}
+
+/// Reads a current-version value file off `r`, verifying its checksum.
+pub(crate) fn decode<V: DeserializeOwned>(r: &mut impl Read) -> Result<V, Error> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)
+        .map_err(|_| Error::CannotReadFromFile)?;
+
+    if bytes.len() < HEADER_LEN || bytes[0..MAGIC.len()] != MAGIC {
+        return Err(Error::UnsupportedFormat);
+    }
+
+    if bytes[MAGIC.len()] != CURRENT_VERSION {
+        return Err(Error::UnsupportedFormat);
+    }
+
+    let checksum_start = PREFIX_LEN;
+    let body_start = HEADER_LEN;
+
+    let expected = &bytes[checksum_start..body_start];
+    let body = &bytes[body_start..];
+
+    if blake3::hash(body).as_bytes() != expected {
+        return Err(Error::ChecksumMismatch);
+    }
+
+    serde_cbor::from_slice(body).map_err(|_| Error::CannotReadFromFile)
+}
+
+/// What [`DiskMap::verify`](crate::DiskMap::verify) found when it examined
+/// one value file.
+pub(crate) enum VerifyOutcome<K> {
+    /// Checksum matched; nothing to report.
+    Ok,
+    /// Checksum didn't match, but the key could still be recovered.
+    ChecksumMismatch(K),
+    /// The body is too damaged to parse at all - not even the key survived.
+    Unreadable,
+}
+
+/// Checks a current-version value file's checksum and recovers its key,
+/// without requiring the *value* half of the body to deserialize into its
+/// Rust type: the body is parsed generically first (as a [`serde_cbor::Value`]),
+/// so corruption confined to the value's bytes still leaves the key
+/// readable. Only when the CBOR structure itself is too damaged to parse at
+/// all (see [`VerifyOutcome::Unreadable`]) is the key unrecoverable.
+pub(crate) fn decode_for_verify<K: DeserializeOwned>(r: &mut impl Read) -> Result<VerifyOutcome<K>, Error> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)
+        .map_err(|_| Error::CannotReadFromFile)?;
+
+    if bytes.len() < HEADER_LEN || bytes[0..MAGIC.len()] != MAGIC || bytes[MAGIC.len()] != CURRENT_VERSION {
+        return Err(Error::UnsupportedFormat);
+    }
+
+    let expected = &bytes[PREFIX_LEN..HEADER_LEN];
+    let body = &bytes[HEADER_LEN..];
+
+    let checksum_matches = blake3::hash(body).as_bytes() == expected;
+
+    let value: serde_cbor::Value = match serde_cbor::from_slice(body) {
+        Ok(value) => value,
+        Err(_) => return Ok(VerifyOutcome::Unreadable),
+    };
+
+    let key_value = match value {
+        serde_cbor::Value::Array(mut items) if items.len() == 2 => items.remove(0),
+        _ => return Ok(VerifyOutcome::Unreadable),
+    };
+
+    let key: K = match serde_cbor::value::from_value(key_value) {
+        Ok(key) => key,
+        Err(_) => return Ok(VerifyOutcome::Unreadable),
+    };
+
+    if checksum_matches {
+        Ok(VerifyOutcome::Ok)
+    } else {
+        Ok(VerifyOutcome::ChecksumMismatch(key))
+    }
+}
+
+/// Whether `bytes` is already a well-formed [`CURRENT_VERSION`] file (magic
+/// and version match; the checksum isn't re-verified here - that's what
+/// [`DiskMap::verify`](crate::DiskMap::verify) is for).
+pub(crate) fn is_current(bytes: &[u8]) -> bool {
+    bytes.len() >= PREFIX_LEN && bytes[0..MAGIC.len()] == MAGIC && bytes[MAGIC.len()] == CURRENT_VERSION
+}
+
+/// Reads a value file written under any pre-[`CURRENT_VERSION`] bare-value
+/// format this crate has ever produced - v1 (magic + version, no checksum),
+/// v2 (adds a checksum), and bare CBOR from before format versioning existed
+/// at all - returning the bare value. Used only by
+/// [`DiskMap::upgrade`](crate::DiskMap::upgrade); it doesn't handle
+/// [`CURRENT_VERSION`] itself (that's [`decode`]), since that format's body
+/// is no longer a bare value.
+pub(crate) fn decode_legacy<V: DeserializeOwned>(bytes: &[u8]) -> Result<V, Error> {
+    let body = if bytes.len() >= MAGIC.len() && bytes[0..MAGIC.len()] == MAGIC {
+        match bytes[MAGIC.len()] {
+            1 => &bytes[PREFIX_LEN..],
+            2 => {
+                if bytes.len() < HEADER_LEN {
+                    return Err(Error::UnsupportedFormat);
+                }
+
+                let expected = &bytes[PREFIX_LEN..HEADER_LEN];
+                let body = &bytes[HEADER_LEN..];
+
+                if blake3::hash(body).as_bytes() != expected {
+                    return Err(Error::ChecksumMismatch);
+                }
+
+                body
+            }
+            _ => return Err(Error::UnsupportedFormat),
+        }
+    } else {
+        bytes
+    };
+
+    serde_cbor::from_slice(body).map_err(|_| Error::CannotReadFromFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A value big enough to need CBOR's 8-byte integer encoding, so flipping
+    // the body's last byte (the payload's low-order byte) changes the value
+    // without perturbing the header bytes that the rest of the test relies
+    // on staying valid CBOR.
+    fn round_trip_bytes() -> Vec<u8> {
+        encode(&("key".to_owned(), 1_000_000_000_000i64)).unwrap()
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let bytes = round_trip_bytes();
+        let mut cursor: &[u8] = &bytes;
+
+        let decoded: (String, i64) = decode(&mut cursor).unwrap();
+        assert_eq!(decoded, ("key".to_owned(), 1_000_000_000_000));
+    }
+
+    #[test]
+    fn decode_detects_checksum_mismatch() {
+        let mut bytes = round_trip_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        let mut cursor: &[u8] = &bytes;
+        let result: Result<(String, i64), Error> = decode(&mut cursor);
+
+        assert!(matches!(result, Err(Error::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn decode_legacy_reads_pre_versioning_cbor() {
+        let bytes = serde_cbor::to_vec(&1_000_000_000_000i64).unwrap();
+        let value: i64 = decode_legacy(&bytes).unwrap();
+
+        assert_eq!(value, 1_000_000_000_000);
+    }
+
+    #[test]
+    fn decode_legacy_reads_v2_checksummed_body() {
+        let body = serde_cbor::to_vec(&1_000_000_000_000i64).unwrap();
+        let checksum = blake3::hash(&body);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(2);
+        bytes.extend_from_slice(checksum.as_bytes());
+        bytes.extend_from_slice(&body);
+
+        let value: i64 = decode_legacy(&bytes).unwrap();
+        assert_eq!(value, 1_000_000_000_000);
+    }
+
+    #[test]
+    fn decode_legacy_rejects_current_version() {
+        let bytes = encode(&1_000_000_000_000i64).unwrap();
+        let result: Result<i64, Error> = decode_legacy(&bytes);
+
+        assert!(matches!(result, Err(Error::UnsupportedFormat)));
+    }
+
+    #[test]
+    fn decode_for_verify_reports_mismatch_without_erroring() {
+        let mut bytes = round_trip_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+
+        let mut cursor: &[u8] = &bytes;
+        let key: String = match decode_for_verify(&mut cursor).unwrap() {
+            VerifyOutcome::ChecksumMismatch(key) => key,
+            _ => panic!("expected a checksum mismatch"),
+        };
+
+        assert_eq!(key, "key");
+    }
+
+    #[test]
+    fn decode_for_verify_reports_unreadable_when_the_structure_itself_is_gone() {
+        let mut bytes = round_trip_bytes();
+        for b in bytes.iter_mut().skip(HEADER_LEN) {
+            *b = 0xff;
+        }
+
+        let mut cursor: &[u8] = &bytes;
+        let outcome: VerifyOutcome<String> = decode_for_verify(&mut cursor).unwrap();
+
+        assert!(matches!(outcome, VerifyOutcome::Unreadable));
+    }
+
+    #[test]
+    fn is_current_accepts_only_current_version() {
+        let current = encode(&1i64).unwrap();
+        assert!(is_current(&current));
+
+        let mut v1 = Vec::new();
+        v1.extend_from_slice(&MAGIC);
+        v1.push(1);
+        assert!(!is_current(&v1));
+    }
+}
@@ -1,22 +1,40 @@
-use std::collections::hash_map::RandomState;
-use std::fmt::Display;
 use std::fs::remove_dir_all;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::fs::{create_dir_all, read_dir, remove_file};
+use std::io::Write;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use advisory_lock::{AdvisoryFileLock, FileLockMode};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
+mod encryption;
+mod format;
+mod storage;
+mod transaction;
+
+pub use encryption::EncryptingStorage;
+pub use storage::{LockError, LockMode, PosixStorage, Storage};
+pub use transaction::Transaction;
+
+/// Filenames the map itself owns (WAL, lock and sequence files for
+/// [`transaction`], plus in-flight atomic-write temp files) and that must
+/// never be surfaced as keys by [`DiskMap::get_keys`].
+fn is_reserved_filename(name: &str) -> bool {
+    matches!(name, "tx.wal" | "tx.lock" | "tx.seq") || name.contains(".tmp.")
+}
+
+/// Disambiguates concurrent temp files from the same process (and even the
+/// same thread, across calls) - `process::id()` alone isn't enough, since
+/// two threads writing the same key collide on it.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
-pub struct DiskMap<K, V> {
+pub struct DiskMap<K, V, S = PosixStorage> {
     directory: PathBuf,
     phantom: PhantomData<fn() -> (K, V)>,
-    hasher: RandomState,
+    storage: S,
 }
 
 #[derive(Debug, Clone)]
@@ -28,136 +46,299 @@ pub enum Error {
     CannotAlterFile,
     CannotDeleteFile,
     CannotGetLock,
+    CannotSync,
+    UnsupportedFormat,
+    DecryptionFailed,
+    ChecksumMismatch,
+}
+
+/// A file [`DiskMap::verify`] found to be corrupt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Corruption<K> {
+    /// The checksum didn't match, but the key could still be recovered.
+    Checksum(K),
+    /// The body is too damaged to parse at all - not even the key survived,
+    /// so the file is reported by path instead.
+    Unreadable(PathBuf),
 }
 
 #[allow(dead_code)]
-impl<K, V> DiskMap<K, V>
+impl<K, V, S> DiskMap<K, V, S>
 where
     K: Serialize + DeserializeOwned,
-    K: Display + From<String>,
     K: PartialEq,
     K: Clone,
     V: Serialize + DeserializeOwned,
+    S: Storage,
 {
-    fn filename(&self, key: &K) -> PathBuf {
+    /// Derives a stable, filesystem-safe path for `key` by hashing its
+    /// serialized bytes, rather than formatting the key directly into the
+    /// path - so keys containing `/`, `..`, a null byte, or anything else
+    /// that isn't a valid path segment are handled the same as any other
+    /// key. The original key is stored alongside the value (see
+    /// [`Self::insert`]) so it can be recovered without reversing the hash.
+    ///
+    /// Uses blake3 (already a dependency, for value-file checksums) rather
+    /// than a 64-bit `Hasher`: two distinct keys colliding on a 64-bit digest
+    /// would be unrecoverable (the second `insert` would fail, and the key
+    /// would read as absent with no signal of why), and blake3's 256 bits
+    /// make that negligible.
+    pub(crate) fn filename(&self, key: &K) -> PathBuf {
+        let key_bytes = serde_cbor::to_vec(key).expect("key serialization cannot fail");
+
+        self.directory.join(blake3::hash(&key_bytes).to_hex().as_str())
+    }
+
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// A sibling path in the same directory, used as the staging file for an
+    /// atomic write. Keeping it alongside the real file guarantees the final
+    /// `rename`/`hard_link` lands on the same filesystem. Unique per call -
+    /// pid alone isn't enough, since two threads in the same process (e.g.
+    /// both altering the same key) would otherwise race for the same temp
+    /// path and one would lose to `create_new`.
+    fn temp_filename(&self, fname: &Path) -> PathBuf {
+        let counter = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+
         PathBuf::from(format!(
-            "{}/{}",
-            &self.directory.to_str().unwrap().to_string(),
-            key
+            "{}.tmp.{}.{:?}.{}",
+            fname.to_str().unwrap(),
+            process::id(),
+            std::thread::current().id(),
+            counter
         ))
     }
 
-    pub fn open_new(directory: &str) -> Result<DiskMap<K, V>, Error> {
-        remove_dir_all(directory).ok();
-
-        Self::open(directory)
+    /// fsyncs the map directory itself, so a preceding rename is durable and
+    /// not just visible in the page cache.
+    pub(crate) fn sync_directory(&self) -> Result<(), Error> {
+        self.storage
+            .sync_dir(&self.directory)
+            .map_err(|_| Error::CannotSync)
     }
 
-    pub fn open(directory: &str) -> Result<DiskMap<K, V>, Error> {
-        match create_dir_all(&directory) {
-            Ok(()) => Ok(DiskMap {
-                directory: PathBuf::from(directory.to_string()),
-                phantom: PhantomData,
-                hasher: RandomState::new(),
-            }),
-            Err(_) => Err(Error::CannotOpenDirectory),
+    /// Serializes `key` and `value` into a temp file next to `fname`, fsyncs
+    /// it, then atomically `rename`s it over `fname`. A reader only ever
+    /// sees either the old or the new contents in full, never a truncated
+    /// in-between. `key` is stored alongside `value` so [`Self::get_keys`]
+    /// can recover it without reversing `fname`'s hash.
+    fn write_atomically(&self, key: &K, fname: &Path, value: &V, err: Error) -> Result<(), Error> {
+        let tmp_path = self.temp_filename(fname);
+
+        let mut tmp_file = self
+            .storage
+            .open_write(&tmp_path, true)
+            .map_err(|_| Error::CannotOpenFile)?;
+
+        let bytes = format::encode(&(key, value))?;
+
+        let result = tmp_file
+            .write_all(&bytes)
+            .map_err(|_| err.clone())
+            .and_then(|_| self.storage.sync(&tmp_file).map_err(|_| err.clone()))
+            .and_then(|_| self.storage.rename(&tmp_path, fname).map_err(|_| err.clone()));
+
+        if result.is_err() {
+            self.storage.remove(&tmp_path).ok();
         }
+
+        result?;
+
+        self.sync_directory()
+    }
+
+    /// Constructs a map rooted at `directory`, using `storage` instead of
+    /// the default [`PosixStorage`] backend - e.g. an in-memory backend for
+    /// tests, or one scoped to `tmpfs`.
+    pub fn open_with_storage(directory: &str, storage: S) -> Result<DiskMap<K, V, S>, Error> {
+        storage
+            .create_dir_all(Path::new(directory))
+            .map_err(|_| Error::CannotOpenDirectory)?;
+
+        Ok(DiskMap {
+            directory: PathBuf::from(directory.to_string()),
+            phantom: PhantomData,
+            storage,
+        })
     }
 
     pub fn insert(&self, key: K, value: V) -> Result<(), Error> {
         let fname = self.filename(&key);
+        let tmp_path = self.temp_filename(&fname);
 
-        let file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .append(true)
-            .open(fname);
+        let tmp_file = self.storage.open_write(&tmp_path, true);
+        let bytes = format::encode(&(&key, &value))?;
 
-        match file {
+        let result = match tmp_file {
             Err(_) => Err(Error::CannotOpenFile),
-            Ok(f) => {
-                f.lock(FileLockMode::Exclusive).unwrap();
-                match serde_cbor::to_writer(f, &value) {
-                    Err(_) => Err(Error::CannotInsert),
-                    Ok(v) => Ok(v),
-                }
-            }
-        }
+            Ok(mut f) => self
+                .storage
+                .lock(&f, LockMode::Exclusive)
+                .map_err(|_| Error::CannotGetLock)
+                .and_then(|_| f.write_all(&bytes).map_err(|_| Error::CannotInsert))
+                .and_then(|_| self.storage.sync(&f).map_err(|_| Error::CannotInsert))
+                // hard_link fails if `fname` already exists, so an
+                // in-progress insert never clobbers an existing key.
+                .and_then(|_| {
+                    self.storage
+                        .hard_link(&tmp_path, &fname)
+                        .map_err(|_| Error::CannotInsert)
+                }),
+        };
+
+        self.storage.remove(&tmp_path).ok();
+
+        result?;
+
+        self.sync_directory()
     }
 
     pub fn get(&self, key: &K) -> Result<V, Error> {
-        let fname = self.filename(&key);
+        let fname = self.filename(key);
 
-        match File::open(fname) {
+        match self.storage.open_read(&fname) {
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(Error::DecryptionFailed),
             Err(_) => Err(Error::CannotOpenFile),
-            Ok(f) => match f.lock(FileLockMode::Shared) {
+            Ok(mut f) => match self.storage.lock(&f, LockMode::Shared) {
                 Err(_) => Err(Error::CannotGetLock),
-                Ok(_) => match serde_cbor::from_reader(f) {
-                    Err(_) => Err(Error::CannotReadFromFile),
-                    Ok(v) => Ok(v),
-                },
+                Ok(_) => {
+                    let (stored_key, value): (K, V) = format::decode(&mut f)?;
+
+                    // A mismatch only happens on a hash collision between
+                    // two distinct keys; treat it the same as "no file".
+                    if &stored_key != key {
+                        return Err(Error::CannotOpenFile);
+                    }
+
+                    Ok(value)
+                }
             },
         }
     }
 
-    pub fn alter(&self, key: &K, mut alter_function: impl FnMut(V) -> V) -> Result<(), Error> {
-        let v = self.get(&key)?;
+    /// Recomputes and checks every value's checksum, returning every file
+    /// whose contents don't match what was written - a sign of bit rot or
+    /// corruption introduced after the fact, since the write path itself is
+    /// crash-safe. Lets an operator detect silent damage proactively instead
+    /// of waiting for an unlucky `get`.
+    ///
+    /// Walks the directory directly (like [`Self::upgrade`]) instead of
+    /// going through [`Self::get_keys`]: `get_keys` decodes each file with
+    /// [`format::decode`], which itself fails (and so drops the key) on
+    /// exactly the checksum mismatch this is trying to surface.
+    ///
+    /// The key is recovered independently of the value (see
+    /// [`format::decode_for_verify`]), so corruption confined to the
+    /// value's bytes still reports the right key. Only when the body is
+    /// damaged badly enough that even the key can't be parsed back out is a
+    /// file reported by path instead, via [`Corruption::Unreadable`] -
+    /// never silently dropped.
+    pub fn verify(&self) -> Result<Vec<Corruption<K>>, Error> {
+        let mut corrupted = Vec::new();
+
+        for path in self
+            .storage
+            .list(&self.directory)
+            .map_err(|_| Error::CannotOpenDirectory)?
+        {
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+
+            if is_reserved_filename(&name) {
+                continue;
+            }
 
-        let fname = self.filename(&key);
+            let mut f = match self.storage.open_read(&path) {
+                Ok(f) => f,
+                // Can't even read the file to check it - e.g. a tampered
+                // `EncryptingStorage` file fails its AEAD tag check before
+                // handing back any bytes. Still damaged-but-present, so it's
+                // reported rather than silently dropped.
+                Err(_) => {
+                    corrupted.push(Corruption::Unreadable(path));
+                    continue;
+                }
+            };
+
+            match format::decode_for_verify::<K>(&mut f) {
+                Ok(format::VerifyOutcome::Ok) => {}
+                Ok(format::VerifyOutcome::ChecksumMismatch(key)) => {
+                    corrupted.push(Corruption::Checksum(key))
+                }
+                Ok(format::VerifyOutcome::Unreadable) => corrupted.push(Corruption::Unreadable(path)),
+                // Not even a well-formed current-version file - a foreign
+                // file or an old format, not something `verify` covers.
+                Err(_) => {}
+            }
+        }
 
-        let lfile = File::open(&fname).unwrap();
-        lfile.lock(FileLockMode::Exclusive).ok();
+        Ok(corrupted)
+    }
 
-        let file = OpenOptions::new().write(true).truncate(true).open(fname);
+    pub fn alter(&self, key: &K, mut alter_function: impl FnMut(V) -> V) -> Result<(), Error> {
+        let v = self.get(key)?;
 
-        match file {
-            Err(_) => Err(Error::CannotOpenFile),
-            Ok(f) => match serde_cbor::to_writer(f, &alter_function(v)) {
-                Err(_) => Err(Error::CannotAlterFile),
-                Ok(v) => Ok(v),
-            },
-        }
+        let fname = self.filename(key);
+
+        let lfile = self
+            .storage
+            .open_read(&fname)
+            .map_err(|_| Error::CannotOpenFile)?;
+        self.storage
+            .lock(&lfile, LockMode::Exclusive)
+            .map_err(|_| Error::CannotGetLock)?;
+
+        self.write_atomically(key, &fname, &alter_function(v), Error::CannotAlterFile)
     }
 
     pub fn delete(&self, key: &K) -> Result<(), Error> {
-        let fname = self.filename(&key);
+        let fname = self.filename(key);
 
-        match remove_file(&fname) {
-            Err(_) => Err(Error::CannotDeleteFile),
-            Ok(_) => Ok(()),
-        }
+        self.storage
+            .remove(&fname)
+            .map_err(|_| Error::CannotDeleteFile)
     }
 
     pub fn overwrite(&self, key: K, value: V) -> Result<(), Error> {
         let fname = self.filename(&key);
 
-        let lfile = File::open(&fname).unwrap();
-        lfile.lock(FileLockMode::Exclusive).ok();
-
-        let file = OpenOptions::new().write(true).truncate(true).open(fname);
+        let lfile = self
+            .storage
+            .open_read(&fname)
+            .map_err(|_| Error::CannotOpenFile)?;
+        self.storage
+            .lock(&lfile, LockMode::Exclusive)
+            .map_err(|_| Error::CannotGetLock)?;
 
-        match file {
-            Err(_) => Err(Error::CannotOpenFile),
-            Ok(f) => match serde_cbor::to_writer(f, &value) {
-                Err(_) => Err(Error::CannotAlterFile),
-                Ok(_) => Ok(()),
-            },
-        }
+        self.write_atomically(&key, &fname, &value, Error::CannotAlterFile)
     }
 
+    /// Lists every key by reading it back out of its value file, rather
+    /// than reparsing it from the (now content-addressed, not reversible)
+    /// filename. A file that isn't a readable `(K, V)` pair - most likely a
+    /// value written before keys were stored alongside values - is skipped
+    /// rather than failing the whole scan.
     pub fn get_keys(&self) -> Result<Vec<K>, Error> {
-        match read_dir(&self.directory) {
-            Ok(c) => {
-                let files: Vec<String> = c
-                    .into_iter()
-                    .filter(|r| r.is_ok())
-                    .map(|r| r.unwrap().path())
-                    .map(|r| r.file_name().unwrap().to_owned().into_string().unwrap())
-                    .collect();
-
-                let casted: Vec<K> = files.into_iter().map(|r| r.into()).collect();
-
-                Ok(casted)
+        match self.storage.list(&self.directory) {
+            Ok(paths) => {
+                let mut keys = Vec::new();
+
+                for path in paths {
+                    let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+
+                    if is_reserved_filename(&name) {
+                        continue;
+                    }
+
+                    if let Ok(mut f) = self.storage.open_read(&path) {
+                        if let Ok((key, _)) = format::decode::<(K, V)>(&mut f) {
+                            keys.push(key);
+                        }
+                    }
+                }
+
+                Ok(keys)
             }
             Err(_) => Err(Error::CannotOpenDirectory),
         }
@@ -177,6 +358,10 @@ where
         }
     }
 
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        self.len().map(|len| len == 0)
+    }
+
     pub fn as_vec(&self) -> Result<Vec<(K, V)>, Error> {
         match self.get_keys() {
             Err(e) => Err(e),
@@ -220,6 +405,103 @@ where
     }
 }
 
+/// `transaction` and `upgrade` are only available on the default
+/// [`PosixStorage`] backend: both rely on a WAL/raw-byte rewrite that
+/// assumes a real, fsync-able directory on disk, which a swapped-in backend
+/// (e.g. an in-memory one) need not provide.
+#[allow(dead_code)]
+impl<K, V> DiskMap<K, V, PosixStorage>
+where
+    K: Serialize + DeserializeOwned,
+    K: PartialEq,
+    K: Clone,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn open_new(directory: &str) -> Result<DiskMap<K, V>, Error> {
+        remove_dir_all(directory).ok();
+
+        Self::open(directory)
+    }
+
+    pub fn open(directory: &str) -> Result<DiskMap<K, V>, Error> {
+        let path = PathBuf::from(directory.to_string());
+
+        PosixStorage
+            .create_dir_all(&path)
+            .map_err(|_| Error::CannotOpenDirectory)?;
+
+        transaction::recover(&path)?;
+
+        Ok(DiskMap {
+            directory: path,
+            phantom: PhantomData,
+            storage: PosixStorage,
+        })
+    }
+
+    /// Stages a batch of `insert`/`overwrite`/`delete`/`alter` calls and
+    /// commits them atomically: either every op in `f` lands, or (on a crash
+    /// mid-commit) recovery on the next `open` finishes the job. See
+    /// [`Transaction`].
+    pub fn transaction(
+        &self,
+        f: impl FnOnce(&mut Transaction<K, V>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut tx = Transaction::new(self);
+
+        f(&mut tx)?;
+
+        tx.commit()
+    }
+
+    /// Rewrites every value file that isn't already on
+    /// [`format::CURRENT_VERSION`] onto the current format, atomically. Run
+    /// this after bumping the crate across a breaking serialization change.
+    ///
+    /// This walks the directory directly rather than going through
+    /// [`Self::get_keys`], since a file on an old format can't be listed by
+    /// key until it's decoded. Pre-v3 files (from before the key was stored
+    /// alongside the value) hold a bare value with no key inside at all -
+    /// their only record of the key was the filename itself, back when it
+    /// was the key's `Display` text rather than a content hash - so `K:
+    /// From<String>` is required here (and only here: the rest of the API
+    /// dropped it) to reconstruct the key from that old filename.
+    pub fn upgrade(&self) -> Result<(), Error>
+    where
+        K: From<String>,
+    {
+        for path in self
+            .storage
+            .list(&self.directory)
+            .map_err(|_| Error::CannotOpenDirectory)?
+        {
+            let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+
+            if is_reserved_filename(&name) {
+                continue;
+            }
+
+            let bytes = std::fs::read(&path).map_err(|_| Error::CannotReadFromFile)?;
+
+            if format::is_current(&bytes) {
+                continue;
+            }
+
+            let value: V = format::decode_legacy(&bytes)?;
+            let key = K::from(name);
+
+            let fname = self.filename(&key);
+            self.write_atomically(&key, &fname, &value, Error::CannotAlterFile)?;
+
+            if fname != path {
+                self.storage.remove(&path).ok();
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::{collections::HashSet, thread, time::Duration};
@@ -301,7 +583,7 @@ mod test {
             z: i32,
         }
 
-        impl Display for ComplexKey {
+        impl std::fmt::Display for ComplexKey {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 write!(f, "{}_{}_{}", self.x, self.y, self.z)
             }
@@ -365,4 +647,183 @@ mod test {
 
         thread::sleep(Duration::from_millis(2000));
     }
+
+    #[test]
+    fn arbitrary_byte_keys() {
+        let d: DiskMap<String, i32> = DiskMap::open_new("/tmp/test_db_7").unwrap();
+
+        let tricky_keys = vec![
+            "a/b/../c".to_owned(),
+            "../../etc/passwd".to_owned(),
+            "with\0null".to_owned(),
+            "x".repeat(500),
+        ];
+
+        for key in &tricky_keys {
+            d.insert(key.clone(), 1).unwrap();
+        }
+
+        for key in &tricky_keys {
+            assert_eq!(d.get(key).unwrap(), 1);
+        }
+
+        let mut keys = d.get_keys().unwrap();
+        keys.sort();
+        let mut expected = tricky_keys.clone();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let d: DiskMap<String, i64> = DiskMap::open_new("/tmp/test_db_8").unwrap();
+
+        d.insert("a".to_owned(), 1_000_000_000_000).unwrap();
+        d.insert("b".to_owned(), 2_000_000_000_000).unwrap();
+
+        assert!(d.verify().unwrap().is_empty());
+
+        let fname = d.filename(&"a".to_owned());
+        let mut bytes = std::fs::read(&fname).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        std::fs::write(&fname, &bytes).unwrap();
+
+        let corrupted = d.verify().unwrap();
+        assert_eq!(corrupted, vec![Corruption::Checksum("a".to_owned())]);
+    }
+
+    #[test]
+    fn verify_reports_unreadable_files_by_path_when_even_the_key_is_gone() {
+        let d: DiskMap<String, i64> = DiskMap::open_new("/tmp/test_db_13").unwrap();
+
+        d.insert("a".to_owned(), 1).unwrap();
+
+        let fname = d.filename(&"a".to_owned());
+        let mut bytes = std::fs::read(&fname).unwrap();
+        // Wipes the whole body (everything past magic + version + checksum,
+        // a fixed 37 bytes) rather than flipping one byte - this breaks the
+        // CBOR structure itself, not just the value's type, so not even the
+        // key can be recovered.
+        for b in bytes.iter_mut().skip(37) {
+            *b = 0xff;
+        }
+        std::fs::write(&fname, &bytes).unwrap();
+
+        let corrupted = d.verify().unwrap();
+        assert_eq!(corrupted, vec![Corruption::Unreadable(fname)]);
+    }
+
+    #[test]
+    fn verify_reports_a_file_that_fails_to_even_open() {
+        let dir = "/tmp/test_db_15";
+        remove_dir_all(dir).ok();
+
+        let d: DiskMap<String, i32, EncryptingStorage<PosixStorage>> =
+            DiskMap::open_encrypted(dir, [5u8; 32]).unwrap();
+
+        d.insert("a".to_owned(), 1).unwrap();
+
+        // Tampering the ciphertext fails the AEAD tag check in
+        // `EncryptingStorage::open_read` itself, before `decode_for_verify`
+        // ever sees any bytes - this must still be reported, not skipped.
+        let fname = d.filename(&"a".to_owned());
+        let mut bytes = std::fs::read(&fname).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&fname, &bytes).unwrap();
+
+        let corrupted = d.verify().unwrap();
+        assert_eq!(corrupted, vec![Corruption::Unreadable(fname)]);
+    }
+
+    #[test]
+    fn upgrade_migrates_a_legacy_value() {
+        let dir = "/tmp/test_db_9";
+        remove_dir_all(dir).ok();
+        std::fs::create_dir_all(dir).unwrap();
+
+        // Simulates a value file from before format versioning existed at
+        // all: bare CBOR, named by the key's literal (pre-content-addressed)
+        // text, as `DiskMap` used to do.
+        let legacy_path = Path::new(dir).join("legacy_key");
+        let body = serde_cbor::to_vec(&42i32).unwrap();
+        std::fs::write(&legacy_path, &body).unwrap();
+
+        let d: DiskMap<String, i32> = DiskMap::open(dir).unwrap();
+        d.upgrade().unwrap();
+
+        assert_eq!(d.get(&"legacy_key".to_owned()).unwrap(), 42);
+        assert!(!legacy_path.exists());
+    }
+
+    #[test]
+    fn encrypted_tamper_returns_decryption_failed() {
+        let dir = "/tmp/test_db_10";
+        remove_dir_all(dir).ok();
+
+        let d: DiskMap<String, i32, EncryptingStorage<PosixStorage>> =
+            DiskMap::open_encrypted(dir, [3u8; 32]).unwrap();
+
+        d.insert("a".to_owned(), 1).unwrap();
+        assert_eq!(d.get(&"a".to_owned()).unwrap(), 1);
+
+        let fname = d.filename(&"a".to_owned());
+        let mut bytes = std::fs::read(&fname).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&fname, &bytes).unwrap();
+
+        assert!(matches!(
+            d.get(&"a".to_owned()),
+            Err(Error::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn transaction_commits_atomically() {
+        let d: DiskMap<String, i32> = DiskMap::open_new("/tmp/test_db_11").unwrap();
+
+        d.insert("a".to_owned(), 1).unwrap();
+
+        d.transaction(|tx| {
+            tx.insert("b".to_owned(), 2)?;
+            tx.overwrite("a".to_owned(), 11)?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(d.get(&"a".to_owned()).unwrap(), 11);
+        assert_eq!(d.get(&"b".to_owned()).unwrap(), 2);
+    }
+
+    #[test]
+    fn transaction_insert_rejects_an_existing_key() {
+        let d: DiskMap<String, i32> = DiskMap::open_new("/tmp/test_db_12").unwrap();
+
+        d.insert("a".to_owned(), 1).unwrap();
+
+        let result = d.transaction(|tx| tx.insert("a".to_owned(), 99));
+        assert!(result.is_err());
+
+        // The conflicting insert must not have clobbered the original value.
+        assert_eq!(d.get(&"a".to_owned()).unwrap(), 1);
+    }
+
+    #[test]
+    fn transaction_is_all_or_nothing_when_a_later_op_conflicts() {
+        let d: DiskMap<String, i32> = DiskMap::open_new("/tmp/test_db_14").unwrap();
+
+        d.insert("existing".to_owned(), 1).unwrap();
+
+        let result = d.transaction(|tx| {
+            tx.insert("fresh".to_owned(), 100)?;
+            tx.insert("existing".to_owned(), 2)
+        });
+        assert!(result.is_err());
+
+        // `fresh` was staged before the conflicting op was even reached, but
+        // the whole transaction must still not have taken effect.
+        assert!(d.get(&"fresh".to_owned()).is_err());
+    }
 }
@@ -0,0 +1,166 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
+
+/// Whether a lock taken via [`Storage::lock`] excludes every other locker
+/// or only other exclusive ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// A failed [`Storage::lock`] call. Callers only ever care that the lock
+/// didn't succeed, not the backend-specific reason why.
+#[derive(Debug, Clone, Copy)]
+pub struct LockError;
+
+/// Abstracts the filesystem operations `DiskMap` needs, the way
+/// rusty-leveldb's `Env` abstracts its filesystem behind `PosixDiskEnv`.
+/// Swap in an in-memory backend for tests, a `tmpfs`-scoped backend, or
+/// (see the encrypting backend) one that transforms bytes in flight,
+/// without touching any map logic.
+pub trait Storage {
+    type File: Read + Write;
+
+    fn create_dir_all(&self, dir: &Path) -> std::io::Result<()>;
+    fn open_read(&self, path: &Path) -> std::io::Result<Self::File>;
+    fn open_write(&self, path: &Path, create_new: bool) -> std::io::Result<Self::File>;
+    fn lock(&self, file: &Self::File, mode: LockMode) -> Result<(), LockError>;
+    fn sync(&self, file: &Self::File) -> std::io::Result<()>;
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()>;
+    fn remove(&self, path: &Path) -> std::io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>>;
+}
+
+/// The default [`Storage`] backend: plain files on the local filesystem,
+/// guarded with advisory (flock-style) locks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PosixStorage;
+
+impl Storage for PosixStorage {
+    type File = File;
+
+    fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn open_read(&self, path: &Path) -> std::io::Result<File> {
+        File::open(path)
+    }
+
+    fn open_write(&self, path: &Path, create_new: bool) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create_new(create_new)
+            .write(true)
+            .open(path)
+    }
+
+    fn lock(&self, file: &File, mode: LockMode) -> Result<(), LockError> {
+        let mode = match mode {
+            LockMode::Shared => FileLockMode::Shared,
+            LockMode::Exclusive => FileLockMode::Exclusive,
+        };
+
+        // Fully qualified: `File::lock` was stabilized in std (no args,
+        // exclusive-only) and would otherwise shadow the `advisory_lock`
+        // trait method of the same name.
+        AdvisoryFileLock::lock(file, mode).map_err(|_| LockError)
+    }
+
+    fn sync(&self, file: &File) -> std::io::Result<()> {
+        file.sync_all()
+    }
+
+    fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn remove(&self, path: &Path) -> std::io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn list(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = Path::new("/tmp/test_storage_round_trip");
+        std::fs::remove_dir_all(dir).ok();
+
+        let storage = PosixStorage;
+
+        storage.create_dir_all(dir).unwrap();
+        let path = dir.join("value");
+
+        let mut f = storage.open_write(&path, true).unwrap();
+        f.write_all(b"hello").unwrap();
+        storage.sync(&f).unwrap();
+
+        let mut out = String::new();
+        storage.open_read(&path).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello");
+
+        assert!(storage.list(dir).unwrap().contains(&path));
+
+        storage.remove(&path).unwrap();
+        assert!(storage.open_read(&path).is_err());
+    }
+
+    #[test]
+    fn open_write_create_new_rejects_an_existing_file() {
+        let dir = Path::new("/tmp/test_storage_create_new");
+        std::fs::remove_dir_all(dir).ok();
+
+        let storage = PosixStorage;
+
+        storage.create_dir_all(dir).unwrap();
+        let path = dir.join("value");
+
+        storage.open_write(&path, true).unwrap();
+        assert!(storage.open_write(&path, true).is_err());
+    }
+
+    #[test]
+    fn rename_replaces_the_destination() {
+        let dir = Path::new("/tmp/test_storage_rename");
+        std::fs::remove_dir_all(dir).ok();
+
+        let storage = PosixStorage;
+
+        storage.create_dir_all(dir).unwrap();
+        let from = dir.join("from");
+        let to = dir.join("to");
+
+        storage.open_write(&from, true).unwrap().write_all(b"hello").unwrap();
+        storage.open_write(&to, true).unwrap().write_all(b"old").unwrap();
+
+        storage.rename(&from, &to).unwrap();
+
+        let mut out = String::new();
+        storage.open_read(&to).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello");
+        assert!(!from.exists());
+    }
+}
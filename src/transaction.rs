@@ -0,0 +1,506 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs::{remove_file, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use advisory_lock::{AdvisoryFileLock, FileLockMode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{DiskMap, Error};
+
+/// A single staged filesystem mutation, as recorded in the write-ahead log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Op {
+    CreateFile { path: PathBuf, data: Vec<u8> },
+    WriteFile { path: PathBuf, data: Vec<u8> },
+    Remove { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum WalRecord {
+    Begin { sequence_number: u64 },
+    Op(Op),
+    End { checksum: u64 },
+}
+
+/// A batch of `insert`/`overwrite`/`delete`/`alter` calls staged for atomic
+/// commit. Build one up inside the closure passed to [`DiskMap::transaction`]
+/// and it is written to a WAL and replayed all-or-nothing when the closure
+/// returns `Ok`.
+pub struct Transaction<'a, K, V> {
+    map: &'a DiskMap<K, V>,
+    ops: Vec<Op>,
+    // Paths this transaction has already staged a `CreateFile` for, so a
+    // second conflicting `insert` in the same transaction is caught here
+    // rather than surfacing mid-`replay` (see `insert`'s doc comment).
+    staged_creates: HashSet<PathBuf>,
+}
+
+impl<'a, K, V> Transaction<'a, K, V>
+where
+    K: Serialize + DeserializeOwned,
+    K: PartialEq,
+    K: Clone,
+    V: Serialize + DeserializeOwned,
+{
+    pub(crate) fn new(map: &'a DiskMap<K, V>) -> Self {
+        Transaction {
+            map,
+            ops: Vec::new(),
+            staged_creates: HashSet::new(),
+        }
+    }
+
+    /// Stages an insert. Unlike `replay`'s `CreateFile` handling, a conflict
+    /// here - the key already has a file on disk, or an earlier call in this
+    /// same transaction already staged one - is checked immediately, before
+    /// the WAL is ever written. Otherwise a conflict caught only during
+    /// `replay` (which runs after the WAL is durably fsynced) would leave
+    /// whichever ops precede it in the batch already applied to disk while
+    /// `commit` still returned `Err` - breaking all-or-nothing semantics.
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        let path = self.map.filename(&key);
+
+        if path.exists() || self.staged_creates.contains(&path) {
+            return Err(Error::CannotInsert);
+        }
+
+        let data = crate::format::encode(&(&key, &value))?;
+
+        self.staged_creates.insert(path.clone());
+        self.ops.push(Op::CreateFile { path, data });
+
+        Ok(())
+    }
+
+    pub fn overwrite(&mut self, key: K, value: V) -> Result<(), Error> {
+        let path = self.map.filename(&key);
+        let data = crate::format::encode(&(&key, &value))?;
+
+        self.ops.push(Op::WriteFile { path, data });
+
+        Ok(())
+    }
+
+    pub fn alter(&mut self, key: &K, mut alter_function: impl FnMut(V) -> V) -> Result<(), Error> {
+        let v = self.map.get(key)?;
+        let path = self.map.filename(key);
+        let data = crate::format::encode(&(key, &alter_function(v)))?;
+
+        self.ops.push(Op::WriteFile { path, data });
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<(), Error> {
+        let path = self.map.filename(key);
+
+        self.ops.push(Op::Remove { path });
+
+        Ok(())
+    }
+
+    /// Writes `tx.wal`, fsyncs it, replays the staged ops onto the real
+    /// files, then deletes the WAL. Serialized across processes by an
+    /// exclusive lock on `tx.lock`.
+    pub(crate) fn commit(self) -> Result<(), Error> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+
+        let dir = self.map.directory();
+
+        let lock_path = dir.join("tx.lock");
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|_| Error::CannotOpenFile)?;
+        // Fully qualified: `File::lock` is now also a std method (no args,
+        // exclusive-only) that would otherwise shadow this trait method.
+        AdvisoryFileLock::lock(&lock_file, FileLockMode::Exclusive)
+            .map_err(|_| Error::CannotGetLock)?;
+
+        let seq_path = dir.join("tx.seq");
+        let next_seq = read_seq(&seq_path) + 1;
+
+        let wal_path = dir.join("tx.wal");
+        write_wal(&wal_path, next_seq, &self.ops)?;
+
+        replay(&self.ops)?;
+
+        write_seq(&seq_path, next_seq)?;
+        self.map.sync_directory()?;
+
+        remove_file(&wal_path).ok();
+        self.map.sync_directory()
+    }
+}
+
+/// Called from [`DiskMap::open`]. If `tx.wal` is present, complete it when
+/// its checksum and sequence number show it was fully fsynced but never
+/// finished replaying; otherwise discard it.
+///
+/// `replay` failing here is not propagated as an `Err` from `open`: staging
+/// now rejects conflicts before a WAL is ever written (see `insert`), so a
+/// WAL that still fails to replay is evidence of a `commit` that crashed
+/// partway through, not a retryable conflict - reapplying it can never
+/// succeed, and refusing to open over it would permanently brick the map.
+/// The WAL is quarantined by simply discarding it; whatever ops it did
+/// manage to apply before crashing stay applied (`replay` is idempotent),
+/// and the sequence number is only advanced on a clean replay.
+///
+/// Ops are replayed one at a time rather than as a single `replay(&ops)`
+/// call, so one op failing (e.g. a `WriteFile` hitting a transient I/O
+/// error) doesn't stop the rest of the batch from being applied - it only
+/// stops the sequence number from advancing.
+pub(crate) fn recover(dir: &Path) -> Result<(), Error> {
+    let wal_path = dir.join("tx.wal");
+
+    if !wal_path.exists() {
+        return Ok(());
+    }
+
+    let seq_path = dir.join("tx.seq");
+    let persisted_seq = read_seq(&seq_path);
+
+    if let Some((sequence_number, ops)) = parse_wal(&wal_path) {
+        if sequence_number == persisted_seq + 1 {
+            // Collect every op's result before checking it - `Iterator::all`
+            // would short-circuit on the first failure and skip replaying
+            // the rest of the batch, the exact bug being fixed here.
+            let results: Vec<_> = ops.iter().map(|op| replay(std::slice::from_ref(op))).collect();
+
+            if results.iter().all(Result::is_ok) {
+                write_seq(&seq_path, sequence_number)?;
+            }
+        }
+    }
+
+    remove_file(&wal_path).ok();
+
+    Ok(())
+}
+
+/// Replays each op onto the real files, fsyncing every file it writes so
+/// the data is actually durable before the WAL recording it is deleted -
+/// otherwise a crash right after that delete could lose a "committed"
+/// write that was still only sitting in the page cache.
+///
+/// Must stay idempotent: `recover` can call this again for a commit that
+/// crashed partway through a previous replay, so re-applying an op that
+/// already landed must not fail or corrupt anything.
+fn replay(ops: &[Op]) -> Result<(), Error> {
+    for op in ops {
+        match op {
+            // Preserves `insert`'s create-new semantics: `create_new` fails
+            // if the target already exists, and that is only accepted when
+            // it already holds exactly this op's data - re-replaying this
+            // exact op (the idempotency case above) is still allowed.
+            Op::CreateFile { path, data } => {
+                match OpenOptions::new()
+                    .create_new(true)
+                    .write(true)
+                    .open(path)
+                {
+                    Ok(mut file) => {
+                        file.write_all(data).map_err(|_| Error::CannotInsert)?;
+                        file.sync_all().map_err(|_| Error::CannotSync)?;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        let existing = std::fs::read(path).map_err(|_| Error::CannotInsert)?;
+                        if existing != *data {
+                            return Err(Error::CannotInsert);
+                        }
+                    }
+                    Err(_) => return Err(Error::CannotInsert),
+                }
+            }
+            Op::WriteFile { path, data } => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path)
+                    .map_err(|_| Error::CannotAlterFile)?;
+                file.write_all(data).map_err(|_| Error::CannotAlterFile)?;
+                file.sync_all().map_err(|_| Error::CannotSync)?;
+            }
+            Op::Remove { path } => match remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(_) => return Err(Error::CannotDeleteFile),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn write_wal(path: &Path, sequence_number: u64, ops: &[Op]) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|_| Error::CannotOpenFile)?;
+
+    let mut hasher = DefaultHasher::new();
+
+    write_record(&mut file, &WalRecord::Begin { sequence_number }, &mut hasher)?;
+
+    for op in ops {
+        write_record(&mut file, &WalRecord::Op(op.clone()), &mut hasher)?;
+    }
+
+    let end = serde_cbor::to_vec(&WalRecord::End {
+        checksum: hasher.finish(),
+    })
+    .map_err(|_| Error::CannotInsert)?;
+    file.write_all(&end).map_err(|_| Error::CannotInsert)?;
+
+    file.sync_all().map_err(|_| Error::CannotSync)
+}
+
+fn write_record(file: &mut File, record: &WalRecord, hasher: &mut DefaultHasher) -> Result<(), Error> {
+    let bytes = serde_cbor::to_vec(record).map_err(|_| Error::CannotInsert)?;
+    hasher.write(&bytes);
+    file.write_all(&bytes).map_err(|_| Error::CannotInsert)
+}
+
+/// Parses and checksum-verifies a WAL file, returning its sequence number
+/// and staged ops if (and only if) it is intact.
+fn parse_wal(path: &Path) -> Option<(u64, Vec<Op>)> {
+    let file = File::open(path).ok()?;
+    let mut records = serde_cbor::Deserializer::from_reader(file).into_iter::<WalRecord>();
+
+    let mut hasher = DefaultHasher::new();
+
+    let begin = records.next()?.ok()?;
+    let sequence_number = match begin {
+        WalRecord::Begin { sequence_number } => sequence_number,
+        _ => return None,
+    };
+    hasher.write(&serde_cbor::to_vec(&begin).ok()?);
+
+    let mut ops = Vec::new();
+
+    loop {
+        let record = records.next()?.ok()?;
+
+        match record {
+            WalRecord::Op(op) => {
+                hasher.write(&serde_cbor::to_vec(&WalRecord::Op(op.clone())).ok()?);
+                ops.push(op);
+            }
+            WalRecord::End { checksum } => {
+                return if checksum == hasher.finish() {
+                    Some((sequence_number, ops))
+                } else {
+                    None
+                };
+            }
+            WalRecord::Begin { .. } => return None,
+        }
+    }
+}
+
+fn read_seq(path: &Path) -> u64 {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0)
+}
+
+fn write_seq(path: &Path, value: u64) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|_| Error::CannotOpenFile)?;
+
+    file.write_all(&value.to_le_bytes())
+        .map_err(|_| Error::CannotInsert)?;
+
+    file.sync_all().map_err(|_| Error::CannotSync)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from(format!("/tmp/test_tx_{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn wal_round_trip() {
+        let dir = tmp_dir("wal_round_trip");
+        let wal_path = dir.join("tx.wal");
+
+        let ops = vec![Op::CreateFile {
+            path: dir.join("value"),
+            data: b"hello".to_vec(),
+        }];
+
+        write_wal(&wal_path, 1, &ops).unwrap();
+
+        let (sequence_number, parsed_ops) = parse_wal(&wal_path).unwrap();
+        assert_eq!(sequence_number, 1);
+        assert_eq!(parsed_ops.len(), 1);
+    }
+
+    #[test]
+    fn parse_wal_rejects_a_truncated_file() {
+        let dir = tmp_dir("wal_truncated");
+        let wal_path = dir.join("tx.wal");
+
+        let ops = vec![Op::CreateFile {
+            path: dir.join("value"),
+            data: b"hello".to_vec(),
+        }];
+
+        write_wal(&wal_path, 1, &ops).unwrap();
+
+        // Simulates a crash mid-write: the End record (and its checksum)
+        // never made it to disk.
+        let mut bytes = std::fs::read(&wal_path).unwrap();
+        bytes.truncate(bytes.len() - 2);
+        std::fs::write(&wal_path, &bytes).unwrap();
+
+        assert!(parse_wal(&wal_path).is_none());
+    }
+
+    #[test]
+    fn recover_replays_an_uncommitted_wal() {
+        let dir = tmp_dir("recover");
+        let value_path = dir.join("value");
+        let wal_path = dir.join("tx.wal");
+
+        let ops = vec![Op::CreateFile {
+            path: value_path.clone(),
+            data: b"hello".to_vec(),
+        }];
+
+        // Simulates a crash after the WAL was fsynced but before replay ran
+        // and the sequence number was bumped.
+        write_wal(&wal_path, 1, &ops).unwrap();
+
+        recover(&dir).unwrap();
+
+        assert_eq!(std::fs::read(&value_path).unwrap(), b"hello");
+        assert!(!wal_path.exists());
+        assert_eq!(read_seq(&dir.join("tx.seq")), 1);
+    }
+
+    #[test]
+    fn recover_ignores_a_wal_already_covered_by_the_seq_file() {
+        let dir = tmp_dir("recover_stale");
+        let value_path = dir.join("value");
+        let wal_path = dir.join("tx.wal");
+        let seq_path = dir.join("tx.seq");
+
+        write_seq(&seq_path, 5).unwrap();
+
+        let ops = vec![Op::CreateFile {
+            path: value_path.clone(),
+            data: b"hello".to_vec(),
+        }];
+        // A WAL whose sequence number the seq file already covers (e.g. left
+        // behind after a successful commit that crashed only on its final
+        // `remove_file`) must not be replayed again.
+        write_wal(&wal_path, 5, &ops).unwrap();
+
+        recover(&dir).unwrap();
+
+        assert!(!value_path.exists());
+        assert!(!wal_path.exists());
+    }
+
+    #[test]
+    fn replay_create_file_is_idempotent_but_rejects_conflicts() {
+        let dir = tmp_dir("replay_idempotent");
+        let path = dir.join("value");
+
+        let op = Op::CreateFile {
+            path: path.clone(),
+            data: b"hello".to_vec(),
+        };
+
+        replay(std::slice::from_ref(&op)).unwrap();
+        // Re-applying the same op, as recovery might after a crash partway
+        // through a previous replay, must not fail.
+        replay(std::slice::from_ref(&op)).unwrap();
+
+        let conflicting = Op::CreateFile {
+            path,
+            data: b"goodbye".to_vec(),
+        };
+        assert!(replay(std::slice::from_ref(&conflicting)).is_err());
+    }
+
+    #[test]
+    fn replay_write_file_always_overwrites() {
+        let dir = tmp_dir("replay_write");
+        let path = dir.join("value");
+
+        std::fs::write(&path, b"old").unwrap();
+
+        let op = Op::WriteFile {
+            path: path.clone(),
+            data: b"new".to_vec(),
+        };
+        replay(std::slice::from_ref(&op)).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn recover_discards_a_wal_that_cannot_be_replayed_instead_of_failing() {
+        let dir = tmp_dir("recover_unreplayable");
+        let value_path = dir.join("value");
+        let wal_path = dir.join("tx.wal");
+
+        // A conflicting value already on disk, as if a prior `CreateFile`
+        // had landed but the transaction that wrote it crashed before its
+        // sequence number was persisted.
+        std::fs::write(&value_path, b"already here").unwrap();
+
+        let ops = vec![Op::CreateFile {
+            path: value_path.clone(),
+            data: b"hello".to_vec(),
+        }];
+        write_wal(&wal_path, 1, &ops).unwrap();
+
+        // Must not return an error - a WAL recovery can never replay
+        // shouldn't permanently prevent `DiskMap::open` from succeeding.
+        recover(&dir).unwrap();
+
+        assert!(!wal_path.exists());
+        assert_eq!(std::fs::read(&value_path).unwrap(), b"already here");
+        assert_eq!(read_seq(&dir.join("tx.seq")), 0);
+    }
+
+    #[test]
+    fn replay_remove_is_idempotent() {
+        let dir = tmp_dir("replay_remove");
+        let path = dir.join("value");
+
+        std::fs::write(&path, b"hello").unwrap();
+
+        let op = Op::Remove { path: path.clone() };
+        replay(std::slice::from_ref(&op)).unwrap();
+        // Already gone - recovery re-replaying this must not fail.
+        replay(std::slice::from_ref(&op)).unwrap();
+
+        assert!(!path.exists());
+    }
+}